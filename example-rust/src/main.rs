@@ -2,29 +2,197 @@ use anyhow::{anyhow, Context, Result};
 use nix::sys::socket as nixsocket;
 use nix::sys::uio::IoVec;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::{FromRawFd, RawFd};
+use std::collections::HashSet;
 use std::process::{Child, Command, Stdio};
+use tokio::io::unix::AsyncFd;
+use tokio::io::AsyncReadExt;
 
+/// The wire protocol version this client speaks. Checked against the
+/// backend's reported version during `Proxy::connect`'s `Initialize`
+/// handshake, using semver compatibility rules (same major version).
+const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// Wire-level request shape: `{"method": ..., "args": [...]}`. `Method`
+/// serializes to exactly this, kept private to the serialization code path
+/// so call sites go through the typed `Method` enum instead.
 #[derive(Serialize)]
-struct Request {
-    method: String,
+struct WireRequest {
+    method: &'static str,
     args: Vec<serde_json::Value>,
 }
 
+/// The set of methods the backend understands, replacing the old
+/// `Request { method: String, args: Vec<Value> }` stringly-typed dispatch.
+/// Serializes to the same `{"method", "args"}` wire shape as before, so the
+/// protocol is unchanged on the socket; what changes is that a caller can no
+/// longer construct e.g. a `GetBlob` with a missing `size` argument.
+#[derive(Debug, Clone)]
+enum Method {
+    Initialize { version: String },
+    GetManifest,
+    GetBlob { digest: String, size: u64 },
+    FinishPipe { pipeid: u32 },
+    Shutdown,
+}
+
+impl Serialize for Method {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let (method, args) = match self {
+            Method::Initialize { version } => ("Initialize", vec![version.clone().into()]),
+            Method::GetManifest => ("GetManifest", vec![]),
+            Method::GetBlob { digest, size } => {
+                ("GetBlob", vec![digest.clone().into(), (*size).into()])
+            }
+            Method::FinishPipe { pipeid } => ("FinishPipe", vec![(*pipeid).into()]),
+            Method::Shutdown => ("Shutdown", vec![]),
+        };
+        WireRequest { method, args }.serialize(serializer)
+    }
+}
+
+/// Associates each `Method` with the reply type it yields and whether a
+/// successful reply carries fds (`HAS_FD`). `Proxy::call` checks the fds it
+/// received against `HAS_FD` at runtime and returns an error on mismatch;
+/// it only checks for some fds versus none, and callers that need exactly
+/// one fd check that themselves via `expect_single_fd`.
+trait TypedMethod {
+    type Reply: serde::de::DeserializeOwned;
+    const HAS_FD: bool;
+    fn into_method(self) -> Method;
+}
+
+struct Initialize(String);
+impl TypedMethod for Initialize {
+    type Reply = InitializeReply;
+    const HAS_FD: bool = false;
+    fn into_method(self) -> Method {
+        Method::Initialize { version: self.0 }
+    }
+}
+
+struct GetManifest;
+impl TypedMethod for GetManifest {
+    type Reply = String;
+    const HAS_FD: bool = true;
+    fn into_method(self) -> Method {
+        Method::GetManifest
+    }
+}
+
+struct GetBlob {
+    digest: String,
+    size: u64,
+}
+impl TypedMethod for GetBlob {
+    type Reply = ();
+    const HAS_FD: bool = true;
+    fn into_method(self) -> Method {
+        Method::GetBlob {
+            digest: self.digest,
+            size: self.size,
+        }
+    }
+}
+
+struct FinishPipe(u32);
+impl TypedMethod for FinishPipe {
+    type Reply = ();
+    const HAS_FD: bool = false;
+    fn into_method(self) -> Method {
+        Method::FinishPipe { pipeid: self.0 }
+    }
+}
+
+struct Shutdown;
+impl TypedMethod for Shutdown {
+    type Reply = ();
+    const HAS_FD: bool = false;
+    fn into_method(self) -> Method {
+        Method::Shutdown
+    }
+}
+
 #[derive(Deserialize)]
 struct Reply {
     success: bool,
     error: String,
     pipeid: u32,
+    /// Number of fds the sender attached via `SCM_RIGHTS`, when the backend
+    /// declares it explicitly — cross-checked against what `recvmsg`
+    /// actually handed back so truncated control data is detected instead
+    /// of silently dropping descriptors. Optional and defaulted because the
+    /// existing wire protocol doesn't emit this field; when absent we fall
+    /// back to validating fd presence against `pipeid` alone.
+    #[serde(default)]
+    fd_count: Option<u32>,
     value: serde_json::Value,
 }
 
+/// A backend's explicit `{"success": false}` rejection of a request, kept
+/// distinct from transport/parse failures (a bad `recvmsg`, unparseable
+/// JSON, ...) so callers like `Proxy::initialize` can match on *this*
+/// specifically instead of string-matching an error message that's only
+/// meant for display.
+#[derive(Debug)]
+struct RemoteError(String);
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "remote error: {}", self.0)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Cross-check the fds actually received via `SCM_RIGHTS` against a reply's
+/// declared `fd_count` (when the backend sends one) and its `pipeid`.
+fn validate_reply_fds(reply: &Reply, fds: &[File]) -> Result<()> {
+    if let Some(fd_count) = reply.fd_count.filter(|&n| fds.len() != n as usize) {
+        return Err(anyhow!(
+            "reply declared {} fds but received {}",
+            fd_count,
+            fds.len()
+        ));
+    }
+    match (fds.is_empty(), reply.pipeid) {
+        (true, 0) => Ok(()),
+        (true, n) => Err(anyhow!("got no fds with pipeid {}", n)),
+        (false, 0) => Err(anyhow!("got fds but no pipeid")),
+        (false, _) => Ok(()),
+    }
+}
+
+/// Require exactly one fd out of a reply's `SCM_RIGHTS` payload, for the
+/// methods (`GetManifest`, `GetBlob`) that only ever hand back a single pipe.
+fn expect_single_fd(fds: Vec<File>) -> Result<File> {
+    let mut fds = fds.into_iter();
+    let fd = fds.next().ok_or_else(|| anyhow!("Missing fd from reply"))?;
+    if fds.next().is_some() {
+        return Err(anyhow!("expected exactly one fd in reply, got more"));
+    }
+    Ok(fd)
+}
+
 struct Proxy {
     sockfd: File,
     proc: Child,
+    protocol_version: semver::Version,
+    capabilities: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+struct InitializeReply {
+    version: String,
+    #[serde(default)]
+    capabilities: Vec<String>,
 }
 
 struct GetManifestReply {
@@ -32,20 +200,250 @@ struct GetManifestReply {
     manifest: Vec<u8>,
 }
 
+/// The underlying source a `BlobReader` streams from: either the `GetBlob`
+/// pipe directly, or — for `Proxy::get_blobs`, which drains each pipe on a
+/// background thread up front so one slow reader can't back up the
+/// backend's reply loop (see its doc comment) — the already fully-drained
+/// and verified bytes.
+enum BlobSource {
+    Pipe(std::io::BufReader<File>),
+    Buffered(std::io::Cursor<Vec<u8>>),
+}
+
+impl Read for BlobSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BlobSource::Pipe(r) => r.read(buf),
+            BlobSource::Buffered(r) => std::io::Read::read(r, buf),
+        }
+    }
+}
+
+/// A `Read`-able handle on a blob that hashes bytes as they stream through
+/// and verifies them against the requested digest and declared size at EOF,
+/// so a blob fetched with `Proxy::get_blob` never needs to be buffered in
+/// memory just to be checked for tampering or truncation. Readers returned
+/// by `Proxy::get_blobs` instead hold the whole blob in memory, already
+/// verified (see `from_buffered`).
+struct BlobReader {
+    inner: BlobSource,
+    hasher: Sha256,
+    expected_digest: String,
+    expected_size: u64,
+    bytes_read: u64,
+    pipeid: u32,
+    verified: bool,
+}
+
+impl BlobReader {
+    fn new(fd: File, expected_digest: String, expected_size: u64, pipeid: u32) -> Self {
+        Self {
+            inner: BlobSource::Pipe(std::io::BufReader::new(fd)),
+            hasher: Sha256::new(),
+            expected_digest,
+            expected_size,
+            bytes_read: 0,
+            pipeid,
+            verified: false,
+        }
+    }
+
+    /// Build a reader over blob bytes already drained and verified up
+    /// front (see `Proxy::get_blobs`), rather than streaming them lazily
+    /// from a pipe. Fails immediately if the drained bytes don't match the
+    /// expected size or digest, instead of deferring that to `Read`.
+    fn from_buffered(
+        bytes: Vec<u8>,
+        expected_digest: String,
+        expected_size: u64,
+        pipeid: u32,
+    ) -> Result<Self> {
+        if bytes.len() as u64 != expected_size {
+            return Err(anyhow!(
+                "blob size mismatch: expected {} got {}",
+                expected_size,
+                bytes.len()
+            ));
+        }
+        let digest = format!("sha256:{:x}", Sha256::digest(&bytes));
+        if digest != expected_digest {
+            return Err(anyhow!(
+                "blob digest mismatch: expected {} got {}",
+                expected_digest,
+                digest
+            ));
+        }
+        Ok(Self {
+            inner: BlobSource::Buffered(std::io::Cursor::new(bytes)),
+            hasher: Sha256::new(),
+            expected_digest,
+            expected_size,
+            bytes_read: 0,
+            pipeid,
+            verified: true,
+        })
+    }
+
+    /// Consume the reader and tell the proxy the pipe is done, failing if
+    /// EOF was never reached or the digest didn't match what was streamed.
+    fn finish(self, proxy: &mut Proxy) -> Result<()> {
+        if !self.verified {
+            return Err(anyhow!(
+                "blob pipe for {} was not fully read and verified",
+                self.expected_digest
+            ));
+        }
+        proxy.finish_pipe(self.pipeid)
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if !self.verified {
+                if self.bytes_read != self.expected_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "blob size mismatch: expected {} got {}",
+                            self.expected_size, self.bytes_read
+                        ),
+                    ));
+                }
+                let digest = format!("sha256:{:x}", self.hasher.clone().finalize());
+                if digest != self.expected_digest {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "blob digest mismatch: expected {} got {}",
+                            self.expected_digest, digest
+                        ),
+                    ));
+                }
+                self.verified = true;
+            }
+        } else if !self.verified {
+            self.bytes_read += n as u64;
+            if self.bytes_read > self.expected_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("blob exceeded declared size {}", self.expected_size),
+                ));
+            }
+            self.hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
 impl Proxy {
-    fn new(sockfd: File, proc: Child) -> Self {
-        Self { sockfd, proc }
+    /// Wrap an already-spawned proxy child's socket end. We don't attempt to
+    /// authenticate the child here: `SO_PEERCRED` on a `socketpair()` fd
+    /// reports the credentials in effect at `socketpair()` time, i.e. our
+    /// own process, not whatever got `exec`'d over the other end, so a
+    /// pid/uid check against `proc` can never actually fail and would only
+    /// give callers false confidence. Verifying the identity of a process on
+    /// the other end of a socketpair isn't something this transport can do;
+    /// that would need a different mechanism (e.g. the child proving itself
+    /// over the channel, or a socket type that actually conveys the peer's
+    /// creds at send time).
+    fn new(sockfd: File, proc: Child) -> Result<Self> {
+        Ok(Self {
+            sockfd,
+            proc,
+            protocol_version: semver::Version::new(0, 0, 0),
+            capabilities: HashSet::new(),
+        })
+    }
+
+    /// Connect to an already-spawned proxy child and perform the
+    /// `Initialize` handshake before returning it: negotiates the protocol
+    /// version and advertised capabilities so later methods can fail fast
+    /// against a mismatched backend instead of sending a method it doesn't
+    /// understand.
+    fn connect(sockfd: File, proc: Child) -> Result<Self> {
+        let mut proxy = Self::new(sockfd, proc)?;
+        proxy.initialize()?;
+        Ok(proxy)
+    }
+
+    /// Perform the `Initialize` handshake, falling back to a capability-less
+    /// "legacy" mode rather than failing `connect` outright if the backend
+    /// rejects the method outright (i.e. replies with `success: false`
+    /// rather than a parseable `InitializeReply`). This isn't a feature
+    /// we've confirmed every `container-image-proxy` build implements, so a
+    /// backend that predates it should still be usable for `GetManifest`
+    /// instead of being refused a connection entirely; it'll simply report
+    /// no capabilities, so `get_blob`/`get_blobs` fail fast with a clear
+    /// error instead of sending a method such a backend won't understand.
+    fn initialize(&mut self) -> Result<()> {
+        let init = match self.call(Initialize(PROTOCOL_VERSION.to_string())) {
+            Ok((init, _fds, _pipeid)) => init,
+            Err(e) if e.downcast_ref::<RemoteError>().is_some() => {
+                self.protocol_version = semver::Version::new(0, 0, 0);
+                self.capabilities = HashSet::new();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let server_version = semver::Version::parse(&init.version).with_context(|| {
+            format!(
+                "Parsing container-image-proxy protocol version {:?}",
+                init.version
+            )
+        })?;
+        let our_version = semver::Version::parse(PROTOCOL_VERSION).unwrap();
+        // Same-major is compatible in either direction: a server one minor
+        // release ahead of or behind us (but not across a major bump) still
+        // understands everything we're about to send it. Building the req
+        // from `our_version` itself (`^our_version`) would instead require
+        // the server to be >= us, wrongly rejecting an older same-major
+        // backend.
+        let compatible =
+            semver::VersionReq::parse(&format!("^{}.0.0", our_version.major)).unwrap();
+        if !compatible.matches(&server_version) {
+            return Err(anyhow!(
+                "container-image-proxy protocol version {} is incompatible with our {}",
+                server_version,
+                our_version
+            ));
+        }
+        self.protocol_version = server_version;
+        self.capabilities = init.capabilities.into_iter().collect();
+        Ok(())
+    }
+
+    /// Whether the connected backend advertised support for `name` (e.g.
+    /// `"GetBlob"`) during the `Initialize` handshake.
+    fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.contains(name)
     }
 
-    fn send_request(&mut self, req: Request) -> Result<()> {
-        let buf = serde_json::to_vec(&req)?;
+    fn send_request(&mut self, method: &Method) -> Result<()> {
+        let buf = serde_json::to_vec(method)?;
         nixsocket::send(self.sockfd.as_raw_fd(), &buf, nixsocket::MsgFlags::empty())?;
         Ok(())
     }
 
-    fn get_reply<T: serde::de::DeserializeOwned>(&mut self) -> Result<(T, Option<(File, u32)>)> {
+    /// Send a typed `Method` and collect its reply, returning an error if a
+    /// successful reply's fds don't match `M::HAS_FD` (missing when required,
+    /// or present when not expected). The check happens at runtime.
+    fn call<M: TypedMethod>(&mut self, method: M) -> Result<(M::Reply, Vec<File>, u32)> {
+        self.send_request(&method.into_method())?;
+        let (reply, fds, pipeid) = self.get_reply::<M::Reply>()?;
+        if M::HAS_FD && fds.is_empty() {
+            return Err(anyhow!("Missing fd from reply"));
+        }
+        if !M::HAS_FD && !fds.is_empty() {
+            return Err(anyhow!("Unexpected fd in reply"));
+        }
+        Ok((reply, fds, pipeid))
+    }
+
+    fn get_reply<T: serde::de::DeserializeOwned>(&mut self) -> Result<(T, Vec<File>, u32)> {
         let mut buf = [0u8; 16 * 1024];
-        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 16]);
         let iov = IoVec::from_mut_slice(buf.as_mut());
         let r = nixsocket::recvmsg(
             self.sockfd.as_raw_fd(),
@@ -54,46 +452,24 @@ impl Proxy {
             nixsocket::MsgFlags::MSG_CMSG_CLOEXEC,
         )?;
         let buf = &buf[0..r.bytes];
-        let mut fdret: Option<File> = None;
+        let mut fds: Vec<File> = Vec::new();
         for cmsg in r.cmsgs() {
-            if let nixsocket::ControlMessageOwned::ScmRights(fds) = cmsg {
-                if let Some(&fd) = fds.get(0) {
-                    let fd = unsafe { std::fs::File::from_raw_fd(fd) };
-                    fdret = Some(fd);
-                }
-                break;
+            if let nixsocket::ControlMessageOwned::ScmRights(rawfds) = cmsg {
+                fds.extend(rawfds.into_iter().map(|fd| unsafe { File::from_raw_fd(fd) }));
             }
         }
         let reply: Reply = serde_json::from_slice(buf).context("Deserializing reply")?;
         if !reply.success {
-            return Err(anyhow!("remote error: {}", reply.error));
+            return Err(RemoteError(reply.error).into());
         }
-        let fdret = match (fdret, reply.pipeid) {
-            (Some(fd), n) => {
-                if n == 0 {
-                    return Err(anyhow!("got fd but no pipeid"));
-                }
-                Some((fd, n))
-            }
-            (None, n) => {
-                if n != 0 {
-                    return Err(anyhow!("got no fd with pipeid {}", n));
-                }
-                None
-            }
-        };
-        let reply = serde_json::from_value(reply.value).context("Deserializing value")?;
-        Ok((reply, fdret))
+        validate_reply_fds(&reply, &fds)?;
+        let value = serde_json::from_value(reply.value).context("Deserializing value")?;
+        Ok((value, fds, reply.pipeid))
     }
 
     fn get_manifest(&mut self) -> Result<GetManifestReply> {
-        let req = Request {
-            method: "GetManifest".to_string(),
-            args: vec![],
-        };
-        self.send_request(req)?;
-        let (digest, fd) = self.get_reply::<String>()?;
-        let (fd, pipeid) = fd.ok_or_else(|| anyhow!("Missing fd from reply"))?;
+        let (digest, fds, pipeid) = self.call(GetManifest)?;
+        let fd = expect_single_fd(fds)?;
         // TODO make this async
         let reader = std::thread::spawn(move || -> Result<_> {
             let mut fd = std::io::BufReader::new(fd);
@@ -106,24 +482,114 @@ impl Proxy {
         Ok(GetManifestReply { digest, manifest })
     }
 
-    fn finish_pipe(&mut self, pipeid: u32) -> Result<()> {
-        let req = Request {
-            method: "FinishPipe".to_string(),
-            args: vec![pipeid.into()],
-        };
-        self.send_request(req)?;
-        let (r, fd) = self.get_reply::<()>()?;
-        if fd.is_some() {
-            return Err(anyhow!("Unexpected fd in finish_pipe reply"));
+    fn get_blob(&mut self, digest: &str, size: u64) -> Result<BlobReader> {
+        if !self.has_capability("GetBlob") {
+            return Err(anyhow!(
+                "container-image-proxy backend (protocol {}) does not advertise GetBlob support",
+                self.protocol_version
+            ));
+        }
+        let (_, fds, pipeid) = self.call(GetBlob {
+            digest: digest.to_string(),
+            size,
+        })?;
+        let fd = expect_single_fd(fds)?;
+        Ok(BlobReader::new(fd, digest.to_string(), size, pipeid))
+    }
+
+    /// Pipeline a batch of `GetBlob` requests, keeping at most
+    /// `MAX_IN_FLIGHT_BLOBS` of them outstanding at once rather than writing
+    /// the whole batch up front: a `SEQPACKET` socket has a bounded send
+    /// buffer, so blasting out an unbounded number of requests before
+    /// reading any reply can deadlock against a server whose own writes
+    /// block once *our* receive buffer fills.
+    ///
+    /// Bounding the socket isn't enough on its own, though: layer blobs can
+    /// be hundreds of MB, far bigger than a pipe's ~64KB buffer, and the
+    /// backend is believed to be single-threaded in its reply loop. So as
+    /// soon as we've read a `GetBlob` reply and its fd, we hand that pipe to
+    /// a background thread to drain immediately, rather than stashing it
+    /// unread until the whole batch returns — otherwise the backend would
+    /// block writing blob bytes into a pipe nothing is reading yet, which
+    /// would also wedge its replies to every other request still in flight.
+    /// Each returned `BlobReader` therefore already holds its fully-drained,
+    /// digest- and size-verified bytes instead of a live pipe.
+    ///
+    /// If a reply comes back as an error partway through, requests already
+    /// sent but not yet replied to would otherwise leave their replies (and
+    /// any attached fds) unread on the socket, desyncing it for later use of
+    /// this `Proxy` — so on error we drain and discard those remaining
+    /// in-flight replies before returning.
+    fn get_blobs(&mut self, digests: &[(String, u64)]) -> Result<Vec<BlobReader>> {
+        const MAX_IN_FLIGHT_BLOBS: usize = 8;
+
+        if !self.has_capability("GetBlob") {
+            return Err(anyhow!(
+                "container-image-proxy backend (protocol {}) does not advertise GetBlob support",
+                self.protocol_version
+            ));
         }
+
+        let mut drains = Vec::with_capacity(digests.len());
+        let mut sent = 0;
+        let mut received = 0;
+        let result = (|| -> Result<()> {
+            while drains.len() < digests.len() {
+                while sent < digests.len() && sent - drains.len() < MAX_IN_FLIGHT_BLOBS {
+                    let (digest, size) = &digests[sent];
+                    self.send_request(
+                        &GetBlob {
+                            digest: digest.clone(),
+                            size: *size,
+                        }
+                        .into_method(),
+                    )?;
+                    sent += 1;
+                }
+                let (digest, size) = digests[drains.len()].clone();
+                // Count the reply as consumed even when it is an error, so the
+                // cleanup below doesn't wait for it a second time.
+                let reply = self.get_reply::<()>();
+                received += 1;
+                let (_, fds, pipeid) = reply?;
+                let fd = expect_single_fd(fds)?;
+                drains.push(std::thread::spawn(move || -> Result<BlobReader> {
+                    let mut pipe = std::io::BufReader::new(fd);
+                    let mut bytes = Vec::new();
+                    pipe.read_to_end(&mut bytes)?;
+                    BlobReader::from_buffered(bytes, digest, size, pipeid)
+                }));
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            for drain in drains {
+                let _ = drain.join();
+            }
+            for _ in received..sent {
+                let _ = self.get_reply::<()>();
+            }
+            return Err(e);
+        }
+
+        drains
+            .into_iter()
+            .map(|drain| {
+                drain
+                    .join()
+                    .map_err(|_| anyhow!("blob drain thread panicked"))?
+            })
+            .collect()
+    }
+
+    fn finish_pipe(&mut self, pipeid: u32) -> Result<()> {
+        let (r, _fds, _pipeid) = self.call(FinishPipe(pipeid))?;
         Ok(r)
     }
 
     fn shutdown(mut self) -> Result<()> {
-        self.send_request(Request {
-            method: "Shutdown".to_string(),
-            args: vec![],
-        })?;
+        self.send_request(&Shutdown.into_method())?;
         let r = self.proc.wait()?;
         if !r.success() {
             return Err(anyhow!("proxy exited with error: {}", r));
@@ -132,11 +598,169 @@ impl Proxy {
     }
 }
 
-fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    let image = args
-        .get(1)
-        .ok_or_else(|| anyhow!("Missing required image argument"))?;
+// `AsyncPipe`/`AsyncProxy` are an alternate, fully-async API for embedding
+// this client in an async image-pull pipeline (see the `AsyncProxy` doc
+// comment). `main` drives the synchronous `Proxy` for the bulk of its work,
+// but also smoke-tests this async surface against a second backend instance
+// so it isn't shipped unexercised.
+fn set_nonblocking(f: &File) -> nix::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    let flags = OFlag::from_bits_truncate(fcntl(f.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(f.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// A pipe fd driven through `tokio::io::unix::AsyncFd`, so it can be read
+/// via `tokio::io::AsyncReadExt` instead of burning a dedicated thread.
+struct AsyncPipe(AsyncFd<File>);
+
+impl AsyncPipe {
+    fn new(f: File) -> Result<Self> {
+        set_nonblocking(&f)?;
+        Ok(Self(AsyncFd::new(f)?))
+    }
+}
+
+impl tokio::io::AsyncRead for AsyncPipe {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = match self.0.poll_read_ready(cx) {
+                std::task::Poll::Ready(r) => r?,
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return std::task::Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return std::task::Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Async counterpart to `Proxy`, built on `tokio::io::unix::AsyncFd` so that
+/// draining the manifest pipe and waiting on `FinishPipe` can be driven
+/// concurrently on the same task instead of needing a blocking thread.
+struct AsyncProxy {
+    sockfd: AsyncFd<File>,
+    proc: Child,
+}
+
+impl AsyncProxy {
+    fn new(sockfd: File, proc: Child) -> Result<Self> {
+        set_nonblocking(&sockfd)?;
+        Ok(Self {
+            sockfd: AsyncFd::new(sockfd)?,
+            proc,
+        })
+    }
+
+    async fn send_request(&mut self, method: &Method) -> Result<()> {
+        let buf = serde_json::to_vec(method)?;
+        loop {
+            let mut guard = self.sockfd.writable().await?;
+            match guard.try_io(|inner| {
+                nixsocket::send(inner.get_ref().as_raw_fd(), &buf, nixsocket::MsgFlags::empty())
+                    .map_err(|e| e.into())
+            }) {
+                Ok(r) => {
+                    r?;
+                    return Ok(());
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Async counterpart to `Proxy::call`: send a typed `Method` and collect
+    /// its reply, enforcing `M::HAS_FD` instead of a runtime `Option` check.
+    async fn call<M: TypedMethod>(&mut self, method: M) -> Result<(M::Reply, Vec<File>, u32)> {
+        self.send_request(&method.into_method()).await?;
+        let (reply, fds, pipeid) = self.get_reply::<M::Reply>().await?;
+        if M::HAS_FD && fds.is_empty() {
+            return Err(anyhow!("Missing fd from reply"));
+        }
+        if !M::HAS_FD && !fds.is_empty() {
+            return Err(anyhow!("Unexpected fd in reply"));
+        }
+        Ok((reply, fds, pipeid))
+    }
+
+    async fn get_reply<T: serde::de::DeserializeOwned>(&mut self) -> Result<(T, Vec<File>, u32)> {
+        loop {
+            let mut guard = self.sockfd.readable().await?;
+            let res = guard.try_io(|inner| {
+                let mut buf = [0u8; 16 * 1024];
+                let mut cmsg_buffer = nix::cmsg_space!([RawFd; 16]);
+                let iov = IoVec::from_mut_slice(buf.as_mut());
+                let r = nixsocket::recvmsg(
+                    inner.get_ref().as_raw_fd(),
+                    &[iov],
+                    Some(&mut cmsg_buffer),
+                    nixsocket::MsgFlags::MSG_CMSG_CLOEXEC,
+                )
+                .map_err(std::io::Error::from)?;
+                let mut fds: Vec<File> = Vec::new();
+                for cmsg in r.cmsgs() {
+                    if let nixsocket::ControlMessageOwned::ScmRights(rawfds) = cmsg {
+                        fds.extend(rawfds.into_iter().map(|fd| unsafe { File::from_raw_fd(fd) }));
+                    }
+                }
+                Ok((buf[0..r.bytes].to_vec(), fds))
+            });
+            let (buf, fds) = match res {
+                Ok(r) => r?,
+                Err(_would_block) => continue,
+            };
+            let reply: Reply = serde_json::from_slice(&buf).context("Deserializing reply")?;
+            if !reply.success {
+                return Err(RemoteError(reply.error).into());
+            }
+            validate_reply_fds(&reply, &fds)?;
+            let value = serde_json::from_value(reply.value).context("Deserializing value")?;
+            return Ok((value, fds, reply.pipeid));
+        }
+    }
+
+    async fn get_manifest(&mut self) -> Result<GetManifestReply> {
+        let (digest, fds, pipeid) = self.call(GetManifest).await?;
+        let fd = expect_single_fd(fds)?;
+        let mut pipe = AsyncPipe::new(fd)?;
+        let mut manifest = Vec::new();
+        let (drained, finished) =
+            tokio::join!(pipe.read_to_end(&mut manifest), self.finish_pipe(pipeid));
+        drained.context("Reading manifest pipe")?;
+        finished?;
+        Ok(GetManifestReply { digest, manifest })
+    }
+
+    async fn finish_pipe(&mut self, pipeid: u32) -> Result<()> {
+        let (r, _fds, _pipeid) = self.call(FinishPipe(pipeid)).await?;
+        Ok(r)
+    }
+
+    async fn shutdown(mut self) -> Result<()> {
+        self.send_request(&Shutdown.into_method()).await?;
+        let r = self.proc.wait()?;
+        if !r.success() {
+            return Err(anyhow!("proxy exited with error: {}", r));
+        }
+        Ok(())
+    }
+}
+
+/// Spawn a `container-image-proxy` child for `image`, connected over a
+/// fresh `socketpair()`. Shared by both the synchronous `Proxy` and the
+/// `AsyncProxy` smoke test in `main`, since each needs its own backend
+/// instance (a `Proxy`/`AsyncProxy` takes ownership of the `Child`).
+fn spawn_backend(image: &str) -> Result<(File, Child)> {
     let (mysock, theirsock) = nixsocket::socketpair(
         nixsocket::AddressFamily::Unix,
         nixsocket::SockType::SeqPacket,
@@ -150,14 +774,62 @@ fn main() -> Result<()> {
     proc.arg("--sockfd=0")
         .arg(image)
         .stdin(Stdio::from(theirsock));
-    let proc = proc.spawn()?;
+    Ok((mysock, proc.spawn()?))
+}
+
+fn main() -> Result<()> {
+    let args: Vec<_> = std::env::args().collect();
+    let image = args
+        .get(1)
+        .ok_or_else(|| anyhow!("Missing required image argument"))?;
 
-    let mut proxy = Proxy::new(mysock, proc);
+    let (mysock, proc) = spawn_backend(image)?;
+    let mut proxy = Proxy::connect(mysock, proc)?;
 
     let r = proxy.get_manifest()?;
     println!("digest: {:?} ({} bytes)", r.digest, r.manifest.len());
 
+    if proxy.has_capability("GetBlob") {
+        // Fetch the manifest's own bytes back out via GetBlob, just to
+        // exercise the streaming/verifying reader against a digest+size we
+        // already know are correct.
+        let mut blob = proxy.get_blob(&r.digest, r.manifest.len() as u64)?;
+        let mut fetched = Vec::new();
+        blob.read_to_end(&mut fetched)?;
+        blob.finish(&mut proxy)?;
+        println!("GetBlob verified {} bytes", fetched.len());
+
+        let mut batch = proxy.get_blobs(&[(r.digest.clone(), r.manifest.len() as u64)])?;
+        for reader in &mut batch {
+            let mut fetched = Vec::new();
+            reader.read_to_end(&mut fetched)?;
+        }
+        for reader in batch {
+            reader.finish(&mut proxy)?;
+        }
+    } else {
+        println!("backend does not advertise GetBlob support, skipping");
+    }
+
     proxy.shutdown()?;
 
+    // Nothing above touches `AsyncProxy`; spin up a second backend instance
+    // and drive its `get_manifest` through a small current-thread runtime so
+    // the async surface is actually exercised rather than merely compiled.
+    let (async_sock, async_proc) = spawn_backend(image)?;
+    tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?
+        .block_on(async {
+            let mut proxy = AsyncProxy::new(async_sock, async_proc)?;
+            let r = proxy.get_manifest().await?;
+            println!(
+                "[async] digest: {:?} ({} bytes)",
+                r.digest,
+                r.manifest.len()
+            );
+            proxy.shutdown().await
+        })?;
+
     Ok(())
 }